@@ -1,22 +1,34 @@
 use crate::persistence::embedding::EmbeddingPersistor;
 use crate::persistence::entity::EntityMappingPersistor;
-use crate::persistence::sparse_matrix::SparseMatrixPersistor;
+use crate::persistence::sparse_matrix::{Entry, SparseMatrixPersistor};
 use crate::sparse_matrix::SparseMatrix;
 use fnv::FnvHasher;
 use log::info;
 use memmap::MmapMut;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use rayon::prelude::*;
 use std::fs;
 use std::fs::OpenOptions;
 use std::hash::Hasher;
 use std::sync::Arc;
 
+/// Seed used when the caller doesn't request a specific one.
+pub const DEFAULT_SEED: u64 = 0;
+
+/// Row block size used by the out-of-core backend when the caller doesn't request a specific one.
+pub const DEFAULT_BLOCK_SIZE: usize = 1_000_000;
+
+/// Entity count above which `calculate_embeddings_mmap` switches to the blocked, out-of-core backend.
+pub const BLOCKED_BACKEND_ENTITY_THRESHOLD: usize = 5_000_000;
+
 /// Calculate embeddings in memory.
 pub fn calculate_embeddings<T1, T2, T3>(
     sparse_matrix: &mut SparseMatrix<T1>,
     max_iter: u8,
     entity_mapping_persistor: Arc<T2>,
     embedding_persistor: &mut T3,
+    seed: u64,
 ) where
     T1: SparseMatrixPersistor + Sync,
     T2: EntityMappingPersistor + Sync,
@@ -29,13 +41,433 @@ pub fn calculate_embeddings<T1, T2, T3>(
         sparse_matrix_id: sparse_matrix.get_id(),
         sparse_matrix_persistor: &sparse_matrix.sparse_matrix_persistor,
     };
-    let init = mult.initialize();
+    let init = mult.initialize(seed);
     let res = mult.propagate(max_iter, init);
     mult.persist(res, entity_mapping_persistor, embedding_persistor);
 
     info!("Finalizing embeddings calculations!")
 }
 
+/// A `dimensions x entities` matrix abstraction backed by either a dense
+/// `Vec` or a memory-mapped file.
+pub trait EmbeddingStorage {
+    /// `(entities, dimensions)` held by this storage.
+    fn shape(&self) -> (usize, usize);
+
+    fn get(&self, dim: usize, entity: usize) -> f32;
+
+    fn set(&mut self, dim: usize, entity: usize, value: f32);
+
+    fn add(&mut self, dim: usize, entity: usize, value: f32);
+
+    /// Visits every dimension's column of entities, in parallel, handing
+    /// each one to `f` for mutation.
+    fn par_columns_mut<F>(&mut self, f: F)
+    where
+        F: Fn(usize, &mut dyn Column) + Sync;
+}
+
+/// A single dimension's column of per-entity values.
+pub trait Column {
+    fn get(&self, entity: usize) -> f32;
+    fn set(&mut self, entity: usize, value: f32);
+    fn add(&mut self, entity: usize, value: f32);
+}
+
+impl Column for Vec<f32> {
+    fn get(&self, entity: usize) -> f32 {
+        self[entity]
+    }
+
+    fn set(&mut self, entity: usize, value: f32) {
+        self[entity] = value;
+    }
+
+    fn add(&mut self, entity: usize, value: f32) {
+        self[entity] += value;
+    }
+}
+
+/// Dense, in-memory `EmbeddingStorage` backend.
+pub struct VecStorage {
+    entities: usize,
+    columns: Vec<Vec<f32>>,
+}
+
+impl VecStorage {
+    fn zeroed(entities: usize, dimension: usize) -> Self {
+        VecStorage {
+            entities,
+            columns: vec![vec![0f32; entities]; dimension],
+        }
+    }
+}
+
+impl EmbeddingStorage for VecStorage {
+    fn shape(&self) -> (usize, usize) {
+        (self.entities, self.columns.len())
+    }
+
+    fn get(&self, dim: usize, entity: usize) -> f32 {
+        self.columns[dim][entity]
+    }
+
+    fn set(&mut self, dim: usize, entity: usize, value: f32) {
+        self.columns[dim][entity] = value;
+    }
+
+    fn add(&mut self, dim: usize, entity: usize, value: f32) {
+        self.columns[dim][entity] += value;
+    }
+
+    fn par_columns_mut<F>(&mut self, f: F)
+    where
+        F: Fn(usize, &mut dyn Column) + Sync,
+    {
+        self.columns
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(dim, col)| f(dim, col));
+    }
+}
+
+/// Memory-mapped `EmbeddingStorage` backend.
+pub struct MmapStorage {
+    entities: usize,
+    dimension: usize,
+    mmap: MmapMut,
+}
+
+impl MmapStorage {
+    fn create(file_name: String, entities: usize, dimension: usize) -> Self {
+        let number_of_bytes = (entities * dimension * 4) as u64;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_name)
+            .unwrap();
+        file.set_len(number_of_bytes).unwrap();
+        let mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
+
+        MmapStorage {
+            entities,
+            dimension,
+            mmap,
+        }
+    }
+
+    fn flush(&mut self) {
+        self.mmap.flush().unwrap();
+    }
+
+}
+
+struct MmapColumn<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Column for MmapColumn<'a> {
+    fn get(&self, entity: usize) -> f32 {
+        let start_idx = entity * 4;
+        let pointer: *const u8 = self.bytes[start_idx..start_idx + 4].as_ptr();
+        unsafe { *(pointer as *const f32) }
+    }
+
+    fn set(&mut self, entity: usize, value: f32) {
+        let start_idx = entity * 4;
+        let pointer: *mut u8 = self.bytes[start_idx..start_idx + 4].as_mut_ptr();
+        unsafe { *(pointer as *mut f32) = value };
+    }
+
+    fn add(&mut self, entity: usize, value: f32) {
+        let start_idx = entity * 4;
+        let pointer: *mut u8 = self.bytes[start_idx..start_idx + 4].as_mut_ptr();
+        unsafe { *(pointer as *mut f32) += value };
+    }
+}
+
+impl EmbeddingStorage for MmapStorage {
+    fn shape(&self) -> (usize, usize) {
+        (self.entities, self.dimension)
+    }
+
+    fn get(&self, dim: usize, entity: usize) -> f32 {
+        let start_idx = (dim * self.entities + entity) * 4;
+        let pointer: *const u8 = self.mmap[start_idx..start_idx + 4].as_ptr();
+        unsafe { *(pointer as *const f32) }
+    }
+
+    fn set(&mut self, dim: usize, entity: usize, value: f32) {
+        let start_idx = (dim * self.entities + entity) * 4;
+        let pointer: *mut u8 = self.mmap[start_idx..start_idx + 4].as_mut_ptr();
+        unsafe { *(pointer as *mut f32) = value };
+    }
+
+    fn add(&mut self, dim: usize, entity: usize, value: f32) {
+        let start_idx = (dim * self.entities + entity) * 4;
+        let pointer: *mut u8 = self.mmap[start_idx..start_idx + 4].as_mut_ptr();
+        unsafe { *(pointer as *mut f32) += value };
+    }
+
+    fn par_columns_mut<F>(&mut self, f: F)
+    where
+        F: Fn(usize, &mut dyn Column) + Sync,
+    {
+        let entities = self.entities;
+        self.mmap
+            .par_chunks_mut(entities * 4)
+            .enumerate()
+            .for_each(|(dim, bytes)| f(dim, &mut MmapColumn { bytes }));
+    }
+}
+
+/// Out-of-core `EmbeddingStorage` backend, built on `MmapStorage`, that
+/// propagates row-block by row-block.
+pub struct BlockedMmapStorage {
+    block_size: usize,
+    inner: MmapStorage,
+}
+
+impl BlockedMmapStorage {
+    fn create(file_name: String, entities: usize, dimension: usize, block_size: usize) -> Self {
+        BlockedMmapStorage {
+            block_size,
+            inner: MmapStorage::create(file_name, entities, dimension),
+        }
+    }
+
+    fn num_blocks(&self) -> usize {
+        let (entities, _dimension) = self.inner.shape();
+        entities.div_ceil(self.block_size)
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+}
+
+impl EmbeddingStorage for BlockedMmapStorage {
+    fn shape(&self) -> (usize, usize) {
+        self.inner.shape()
+    }
+
+    fn get(&self, dim: usize, entity: usize) -> f32 {
+        self.inner.get(dim, entity)
+    }
+
+    fn set(&mut self, dim: usize, entity: usize, value: f32) {
+        self.inner.set(dim, entity, value)
+    }
+
+    fn add(&mut self, dim: usize, entity: usize, value: f32) {
+        self.inner.add(dim, entity, value)
+    }
+
+    fn par_columns_mut<F>(&mut self, f: F)
+    where
+        F: Fn(usize, &mut dyn Column) + Sync,
+    {
+        self.inner.par_columns_mut(f)
+    }
+}
+
+/// Groups the sparse matrix's entries by which output row-block their target
+/// row falls into.
+fn group_entries_by_block<T>(persistor: &T, num_blocks: usize, block_size: usize) -> Vec<Vec<Entry>>
+where
+    T: SparseMatrixPersistor,
+{
+    let mut blocks: Vec<Vec<Entry>> = vec![Vec::new(); num_blocks];
+    for j in 0..persistor.get_amount_of_data() {
+        let entry = persistor.get_entry(j);
+        blocks[entry.row as usize / block_size].push(entry);
+    }
+    blocks
+}
+
+/// Out-of-core variant of `next_power_storage`: each output row-block is
+/// filled from its own pre-grouped entries, so writes stay inside that
+/// block's slice of the memory map instead of scattering across all of it.
+/// Blocks are processed in parallel, and reads of `input` still go to
+/// whichever entity an entry's column names.
+fn next_power_blocked(
+    blocks: &[Vec<Entry>],
+    input: &BlockedMmapStorage,
+    mut output: BlockedMmapStorage,
+) -> BlockedMmapStorage {
+    let (entities, dimension) = output.shape();
+    let block_size = output.block_size;
+
+    // Carve the backing byte buffer into one disjoint &mut [u8] per
+    // (block, dimension) up front, so the parallel loop below mutates
+    // through genuine, non-overlapping mutable slices instead of writing
+    // through a pointer derived from a shared reference.
+    let mut block_slices: Vec<Vec<&mut [u8]>> =
+        (0..blocks.len()).map(|_| Vec::with_capacity(dimension)).collect();
+    let mut rest: &mut [u8] = &mut output.inner.mmap;
+    for _dim in 0..dimension {
+        let (dim_chunk, remainder) = rest.split_at_mut(entities * 4);
+        rest = remainder;
+
+        let mut dim_rest = dim_chunk;
+        for (block_idx, slices) in block_slices.iter_mut().enumerate() {
+            let start = block_idx * block_size;
+            let end = (start + block_size).min(entities);
+            let (block_chunk, remainder) = dim_rest.split_at_mut((end - start) * 4);
+            dim_rest = remainder;
+            slices.push(block_chunk);
+        }
+    }
+
+    blocks
+        .par_iter()
+        .zip(block_slices.into_par_iter())
+        .enumerate()
+        .for_each(|(block_idx, (entries, mut dim_chunks))| {
+            let block_start = block_idx * block_size;
+            for (dim, chunk) in dim_chunks.iter_mut().enumerate() {
+                for entry in entries {
+                    let value = input.get(dim, entry.col as usize);
+                    let local_idx = (entry.row as usize - block_start) * 4;
+                    let pointer: *mut u8 = chunk[local_idx..local_idx + 4].as_mut_ptr();
+                    unsafe { *(pointer as *mut f32) += value * entry.value };
+                }
+            }
+        });
+
+    output
+}
+
+/// Fills `storage` with the per-entity, per-dimension starting coordinates.
+fn initialize_storage<T, S>(persistor: &T, seed: u64, mut storage: S) -> S
+where
+    T: SparseMatrixPersistor + Sync,
+    S: EmbeddingStorage + Send,
+{
+    let (entities_count, _dimension) = storage.shape();
+
+    storage.par_columns_mut(|dim, col| {
+        for entity in 0..entities_count {
+            let hsh = persistor.get_hash(entity as u32);
+            if hsh != -1 {
+                col.set(entity, seeded_init_value(seed, hsh, dim as i64));
+            }
+        }
+    });
+
+    storage
+}
+
+/// Computes one propagation step, reading `input` and accumulating into `output`.
+fn next_power_storage<T, S>(persistor: &T, input: &S, mut output: S) -> S
+where
+    T: SparseMatrixPersistor + Sync,
+    S: EmbeddingStorage + Sync,
+{
+    let amount_of_data = persistor.get_amount_of_data();
+
+    output.par_columns_mut(|dim, col| {
+        for j in 0..amount_of_data {
+            let entry = persistor.get_entry(j);
+            let value = input.get(dim, entry.col as usize);
+            col.add(entry.row as usize, value * entry.value);
+        }
+    });
+
+    output
+}
+
+/// L2-normalizes every entity's row across all dimensions in place.
+fn normalize_storage<S>(mut storage: S) -> S
+where
+    S: EmbeddingStorage + Send,
+{
+    let (entities_count, dimension) = storage.shape();
+    let mut row_sum = vec![0f32; entities_count];
+
+    for dim in 0..dimension {
+        for (entity, sum) in row_sum.iter_mut().enumerate() {
+            *sum += storage.get(dim, entity).powi(2);
+        }
+    }
+
+    let row_sum = Arc::new(row_sum);
+    storage.par_columns_mut(|_dim, col| {
+        for entity in 0..entities_count {
+            let sum = row_sum[entity];
+            col.set(entity, col.get(entity) / sum.sqrt());
+        }
+    });
+
+    storage
+}
+
+/// Reads the final embedding matrix out of `storage` and writes it through
+/// the persistors.
+fn persist_storage<T1, T2, T3, S>(
+    persistor: &T1,
+    dimension: u16,
+    storage: &S,
+    entity_mapping_persistor: Arc<T2>,
+    embedding_persistor: &mut T3,
+) where
+    T1: SparseMatrixPersistor,
+    T2: EntityMappingPersistor,
+    T3: EmbeddingPersistor,
+    S: EmbeddingStorage,
+{
+    info!("Start saving embeddings.");
+
+    let entities_count = persistor.get_entity_counter();
+    embedding_persistor.put_metadata(entities_count, dimension);
+
+    for i in 0..entities_count {
+        let hash = persistor.get_hash(i);
+        let entity_name_opt = entity_mapping_persistor.get_entity(hash as u64);
+        if let Some(entity_name) = entity_name_opt {
+            let hash_occur = persistor.get_hash_occurrence(hash as u64);
+            let mut embedding: Vec<f32> = Vec::with_capacity(dimension as usize);
+            for dim in 0..(dimension as usize) {
+                embedding.insert(dim, storage.get(dim, i as usize));
+            }
+            embedding_persistor.put_data(entity_name, hash_occur, embedding);
+        };
+    }
+
+    embedding_persistor.finish();
+
+    info!("Done saving embeddings.");
+}
+
+/// Draws an initial coordinate for `(entity_hash, dimension)`, keyed by `seed`.
+fn seeded_init_value(seed: u64, entity_hash: i64, dimension: i64) -> f32 {
+    // no specific requirement (ca be lower as well)
+    let max_hash = 8 * 1024 * 1024;
+    let max_hash_float = max_hash as f32;
+
+    if seed == DEFAULT_SEED {
+        // Keep the original FNV-based formula for the default seed so
+        // existing outputs don't change.
+        return ((hash(entity_hash + dimension) % max_hash) as f32) / max_hash_float;
+    }
+
+    let mut key = [0u8; 32];
+    key[0..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&entity_hash.to_le_bytes());
+    key[16..24].copy_from_slice(&dimension.to_le_bytes());
+
+    let mut rng = ChaCha20Rng::from_seed(key);
+    ((rng.next_u64() % max_hash as u64) as f32) / max_hash_float
+}
+
+fn hash(num: i64) -> i64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_i64(num);
+    hasher.finish() as i64
+}
+
 /// Provides matrix multiplication based on sparse matrix data.
 #[derive(Debug)]
 pub struct MatrixMultiplicator<'a, T: SparseMatrixPersistor + Sync> {
@@ -48,49 +480,33 @@ impl<'a, T> MatrixMultiplicator<'a, T>
 where
     T: SparseMatrixPersistor + Sync,
 {
-    fn initialize(&self) -> Vec<Vec<f32>> {
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
+    fn initialize(&self, seed: u64) -> VecStorage {
+        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
 
         info!(
-            "Start initialization. Dims: {}, entities: {}.",
-            self.dimension, entities_count
+            "Start initialization. Dims: {}, entities: {}. Seed: {}.",
+            self.dimension, entities_count, seed
         );
 
-        // no specific requirement (ca be lower as well)
-        let max_hash = 8 * 1024 * 1024;
-        let max_hash_float = max_hash as f32;
-
-        let result: Vec<Vec<f32>> = (0..self.dimension)
-            .into_par_iter()
-            .map(|i| {
-                let mut col: Vec<f32> = Vec::with_capacity(entities_count as usize);
-                for j in 0..entities_count {
-                    let hsh = self.sparse_matrix_persistor.get_hash(j);
-                    if hsh != -1 {
-                        let col_value =
-                            ((hash(hsh + (i as i64)) % max_hash) as f32) / max_hash_float;
-                        col.insert(j as usize, col_value);
-                    }
-                }
-                col
-            })
-            .collect();
+        let storage = VecStorage::zeroed(entities_count, self.dimension as usize);
+        let storage = initialize_storage(self.sparse_matrix_persistor, seed, storage);
 
         info!(
             "Done initializing. Dims: {}, entities: {}.",
             self.dimension, entities_count
         );
-        result
+        storage
     }
 
-    fn propagate(&self, max_iter: u8, res: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    fn propagate(&self, max_iter: u8, res: VecStorage) -> VecStorage {
         info!("Start propagating. Number of iterations: {}.", max_iter);
 
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
+        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
         let mut new_res = res;
         for i in 0..max_iter {
-            let next = self.next_power(new_res);
-            new_res = self.normalize(next);
+            let output = VecStorage::zeroed(entities_count, self.dimension as usize);
+            let next = next_power_storage(self.sparse_matrix_persistor, &new_res, output);
+            new_res = normalize_storage(next);
             info!(
                 "Done iter: {}. Dims: {}, entities: {}, num data points: {}.",
                 i,
@@ -103,131 +519,93 @@ where
         new_res
     }
 
-    fn next_power(&self, res: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
-        let rnew = Self::zero_2d(entities_count, self.dimension as usize);
-
-        let amount_of_data = self.sparse_matrix_persistor.get_amount_of_data();
-
-        let result: Vec<Vec<f32>> = res
-            .into_par_iter()
-            .zip(rnew)
-            .update(|data| {
-                let (res_col, rnew_col) = data;
-                for j in 0..amount_of_data {
-                    let entry = self.sparse_matrix_persistor.get_entry(j);
-                    let elem = rnew_col.get_mut(entry.row as usize).unwrap();
-                    let value = res_col.get(entry.col as usize).unwrap();
-                    *elem += *value * entry.value
-                }
-            })
-            .map(|data| data.1)
-            .collect();
-
-        result
-    }
-
-    fn zero_2d(row: usize, col: usize) -> Vec<Vec<f32>> {
-        let mut res: Vec<Vec<f32>> = Vec::with_capacity(col);
-        for i in 0..col {
-            let col = vec![0f32; row];
-            res.insert(i, col);
-        }
-        res
-    }
-
-    fn normalize(&self, res: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
-        let mut row_sum = vec![0f32; entities_count];
-
-        for i in 0..(self.dimension as usize) {
-            for j in 0..entities_count {
-                let sum = row_sum.get_mut(j).unwrap();
-                let col: &Vec<f32> = res.get(i).unwrap();
-                let value = col.get(j).unwrap();
-                *sum += value.powi(2)
-            }
-        }
-
-        let row_sum = Arc::new(row_sum);
-        let result: Vec<Vec<f32>> = res
-            .into_par_iter()
-            .update(|col| {
-                for j in 0..entities_count {
-                    let value = col.get_mut(j).unwrap();
-                    let sum = row_sum.get(j).unwrap();
-                    *value /= sum.sqrt();
-                }
-            })
-            .collect();
-
-        result
-    }
-
     fn persist<T1, T2>(
         &self,
-        res: Vec<Vec<f32>>,
+        res: VecStorage,
         entity_mapping_persistor: Arc<T1>,
         embedding_persistor: &mut T2,
     ) where
         T1: EntityMappingPersistor,
         T2: EmbeddingPersistor,
     {
-        info!("Start saving embeddings.");
-
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
-        embedding_persistor.put_metadata(entities_count, self.dimension);
-
-        for i in 0..entities_count {
-            let hash = self.sparse_matrix_persistor.get_hash(i);
-            let entity_name_opt = entity_mapping_persistor.get_entity(hash as u64);
-            if let Some(entity_name) = entity_name_opt {
-                let hash_occur = self
-                    .sparse_matrix_persistor
-                    .get_hash_occurrence(hash as u64);
-                let mut embedding: Vec<f32> = Vec::with_capacity(self.dimension as usize);
-                for j in 0..(self.dimension as usize) {
-                    let col: &Vec<f32> = res.get(j).unwrap();
-                    let value = col.get(i as usize).unwrap();
-                    embedding.insert(j, *value);
-                }
-                embedding_persistor.put_data(entity_name, hash_occur, embedding);
-            };
-        }
-
-        embedding_persistor.finish();
-
-        info!("Done saving embeddings.");
+        persist_storage(
+            self.sparse_matrix_persistor,
+            self.dimension,
+            &res,
+            entity_mapping_persistor,
+            embedding_persistor,
+        );
     }
 }
 
-fn hash(num: i64) -> i64 {
-    let mut hasher = FnvHasher::default();
-    hasher.write_i64(num);
-    hasher.finish() as i64
+/// Calculate embeddings with memory-mapped files, using `DEFAULT_BLOCK_SIZE`
+/// for the out-of-core backend if the entity count requires it.
+pub fn calculate_embeddings_mmap<T1, T2, T3>(
+    sparse_matrix: &mut SparseMatrix<T1>,
+    max_iter: u8,
+    entity_mapping_persistor: Arc<T2>,
+    embedding_persistor: &mut T3,
+    seed: u64,
+) where
+    T1: SparseMatrixPersistor + Sync,
+    T2: EntityMappingPersistor + Sync,
+    T3: EmbeddingPersistor,
+{
+    calculate_embeddings_mmap_with_block_size(
+        sparse_matrix,
+        max_iter,
+        entity_mapping_persistor,
+        embedding_persistor,
+        seed,
+        DEFAULT_BLOCK_SIZE,
+    )
 }
 
-/// Calculate embeddings with memory-mapped files.
-pub fn calculate_embeddings_mmap<T1, T2, T3>(
+/// Same as `calculate_embeddings_mmap`, but lets the caller tune the row
+/// block size used by the out-of-core backend.
+pub fn calculate_embeddings_mmap_with_block_size<T1, T2, T3>(
     sparse_matrix: &mut SparseMatrix<T1>,
     max_iter: u8,
     entity_mapping_persistor: Arc<T2>,
     embedding_persistor: &mut T3,
+    seed: u64,
+    block_size: usize,
 ) where
     T1: SparseMatrixPersistor + Sync,
     T2: EntityMappingPersistor + Sync,
     T3: EmbeddingPersistor,
 {
+    assert!(block_size > 0, "block_size must be greater than 0");
+
     sparse_matrix.normalize();
 
-    let mult = MatrixMultiplicatorMMap {
-        dimension: sparse_matrix.dimension,
-        sparse_matrix_id: sparse_matrix.get_id(),
-        sparse_matrix_persistor: &sparse_matrix.sparse_matrix_persistor,
-    };
-    let init = mult.initialize();
-    let res = mult.propagate(max_iter, init);
-    mult.persist(res, entity_mapping_persistor, embedding_persistor);
+    let entities_count = sparse_matrix.sparse_matrix_persistor.get_entity_counter() as usize;
+
+    if entities_count > BLOCKED_BACKEND_ENTITY_THRESHOLD {
+        info!(
+            "Entity count {} exceeds {}, using the blocked out-of-core backend (block size {}).",
+            entities_count, BLOCKED_BACKEND_ENTITY_THRESHOLD, block_size
+        );
+
+        let mult = MatrixMultiplicatorBlockedMMap {
+            dimension: sparse_matrix.dimension,
+            sparse_matrix_id: sparse_matrix.get_id(),
+            sparse_matrix_persistor: &sparse_matrix.sparse_matrix_persistor,
+            block_size,
+        };
+        let init = mult.initialize(seed);
+        let res = mult.propagate(max_iter, init);
+        mult.persist(res, entity_mapping_persistor, embedding_persistor);
+    } else {
+        let mult = MatrixMultiplicatorMMap {
+            dimension: sparse_matrix.dimension,
+            sparse_matrix_id: sparse_matrix.get_id(),
+            sparse_matrix_persistor: &sparse_matrix.sparse_matrix_persistor,
+        };
+        let init = mult.initialize(seed);
+        let res = mult.propagate(max_iter, init);
+        mult.persist(res, entity_mapping_persistor, embedding_persistor);
+    }
 
     fs::remove_file(format!("{}_matrix_{}", sparse_matrix.get_id(), max_iter)).unwrap();
 
@@ -246,61 +624,28 @@ impl<'a, T> MatrixMultiplicatorMMap<'a, T>
 where
     T: SparseMatrixPersistor + Sync,
 {
-    fn initialize(&self) -> MmapMut {
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
+    fn initialize(&self, seed: u64) -> MmapStorage {
+        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
 
         info!(
-            "Start initialization. Dims: {}, entities: {}.",
-            self.dimension, entities_count
+            "Start initialization. Dims: {}, entities: {}. Seed: {}.",
+            self.dimension, entities_count, seed
         );
 
-        let number_of_bytes = entities_count as u64 * self.dimension as u64 * 4;
         let file_name = format!("{}_matrix_0", self.sparse_matrix_id);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_name)
-            .unwrap();
-        file.set_len(number_of_bytes).unwrap();
-        let mut mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
-
-        // no specific requirement (ca be lower as well)
-        let max_hash = 8 * 1024 * 1024;
-        let max_hash_float = max_hash as f32;
-
-        mmap.par_chunks_mut((entities_count * 4) as usize)
-            .enumerate()
-            .for_each(|(i, chunk)| {
-                // i - number of dimension
-                // chunk - column/vector of bytes
-                for j in 0..entities_count as usize {
-                    let hsh = self.sparse_matrix_persistor.get_hash(j as u32);
-                    if hsh != -1 {
-                        let col_value =
-                            ((hash(hsh + (i as i64)) % max_hash) as f32) / max_hash_float;
-
-                        let start_idx = j * 4;
-                        let end_idx = start_idx + 4;
-                        let pointer: *mut u8 = (&mut chunk[start_idx..end_idx]).as_mut_ptr();
-                        unsafe {
-                            let value = pointer as *mut f32;
-                            *value = col_value;
-                        };
-                    }
-                }
-            });
+        let storage = MmapStorage::create(file_name, entities_count, self.dimension as usize);
+        let mut storage = initialize_storage(self.sparse_matrix_persistor, seed, storage);
+        storage.flush();
 
         info!(
             "Done initializing. Dims: {}, entities: {}.",
             self.dimension, entities_count
         );
 
-        mmap.flush();
-        mmap
+        storage
     }
 
-    fn propagate(&self, max_iter: u8, res: MmapMut) -> MmapMut {
+    fn propagate(&self, max_iter: u8, res: MmapStorage) -> MmapStorage {
         info!("Start propagating. Number of iterations: {}.", max_iter);
 
         let entities_count = self.sparse_matrix_persistor.get_entity_counter();
@@ -321,135 +666,259 @@ where
         new_res
     }
 
-    fn next_power(&self, iteration: u8, res: MmapMut) -> MmapMut {
+    fn next_power(&self, iteration: u8, res: MmapStorage) -> MmapStorage {
         let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
 
-        let number_of_bytes = entities_count as u64 * self.dimension as u64 * 4;
         let file_name = format!("{}_matrix_{}", self.sparse_matrix_id, iteration + 1);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_name)
-            .unwrap();
-        file.set_len(number_of_bytes).unwrap();
-        let mut mmap_output = unsafe { MmapMut::map_mut(&file).unwrap() };
+        let output = MmapStorage::create(file_name, entities_count, self.dimension as usize);
 
-        let amount_of_data = self.sparse_matrix_persistor.get_amount_of_data();
+        let mut output = next_power_storage(self.sparse_matrix_persistor, &res, output);
+        output.flush();
+        output
+    }
 
-        let input = Arc::new(res);
-        mmap_output
-            .par_chunks_mut(entities_count * 4)
-            .enumerate()
-            .for_each_with(input, |input, (i, chunk)| {
-                for j in 0..amount_of_data {
-                    let entry = self.sparse_matrix_persistor.get_entry(j);
-
-                    let start_idx_input = ((i * entities_count) + entry.col as usize) * 4;
-                    let end_idx_input = start_idx_input + 4;
-                    let pointer: *const u8 = (&input[start_idx_input..end_idx_input]).as_ptr();
-                    let input_value = unsafe {
-                        let value = pointer as *const f32;
-                        *value
-                    };
-
-                    let start_idx_output = entry.row as usize * 4;
-                    let end_idx_output = start_idx_output + 4;
-                    let pointer: *mut u8 =
-                        (&mut chunk[start_idx_output..end_idx_output]).as_mut_ptr();
-                    unsafe {
-                        let value = pointer as *mut f32;
-                        *value += input_value * entry.value;
-                    };
-                }
-            });
+    fn normalize(&self, res: MmapStorage) -> MmapStorage {
+        let mut res = normalize_storage(res);
+        res.flush();
+        res
+    }
 
-        mmap_output.flush();
-        mmap_output
+    fn persist<T1, T2>(
+        &self,
+        res: MmapStorage,
+        entity_mapping_persistor: Arc<T1>,
+        embedding_persistor: &mut T2,
+    ) where
+        T1: EntityMappingPersistor,
+        T2: EmbeddingPersistor,
+    {
+        persist_storage(
+            self.sparse_matrix_persistor,
+            self.dimension,
+            &res,
+            entity_mapping_persistor,
+            embedding_persistor,
+        );
     }
+}
 
-    fn normalize(&self, mut res: MmapMut) -> MmapMut {
+/// Provides matrix multiplication using the out-of-core `BlockedMmapStorage` backend.
+#[derive(Debug)]
+pub struct MatrixMultiplicatorBlockedMMap<'a, T: SparseMatrixPersistor + Sync> {
+    pub dimension: u16,
+    pub sparse_matrix_id: String,
+    pub sparse_matrix_persistor: &'a T,
+    pub block_size: usize,
+}
+
+impl<'a, T> MatrixMultiplicatorBlockedMMap<'a, T>
+where
+    T: SparseMatrixPersistor + Sync,
+{
+    fn initialize(&self, seed: u64) -> BlockedMmapStorage {
         let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
-        let mut row_sum = vec![0f32; entities_count];
 
-        for i in 0..(self.dimension as usize) {
-            for j in 0..entities_count {
-                let sum = row_sum.get_mut(j).unwrap();
+        info!(
+            "Start initialization. Dims: {}, entities: {}. Seed: {}. Block size: {}.",
+            self.dimension, entities_count, seed, self.block_size
+        );
 
-                let start_idx = ((i * entities_count) + j) * 4;
-                let end_idx = start_idx + 4;
-                let pointer: *const u8 = (&res[start_idx..end_idx]).as_ptr();
-                let value = unsafe {
-                    let value = pointer as *const f32;
-                    *value
-                };
+        let file_name = format!("{}_matrix_0", self.sparse_matrix_id);
+        let storage = BlockedMmapStorage::create(
+            file_name,
+            entities_count,
+            self.dimension as usize,
+            self.block_size,
+        );
+        let mut storage = initialize_storage(self.sparse_matrix_persistor, seed, storage);
+        storage.flush();
 
-                *sum += value.powi(2)
-            }
+        info!(
+            "Done initializing. Dims: {}, entities: {}.",
+            self.dimension, entities_count
+        );
+
+        storage
+    }
+
+    fn propagate(&self, max_iter: u8, res: BlockedMmapStorage) -> BlockedMmapStorage {
+        info!("Start propagating. Number of iterations: {}.", max_iter);
+
+        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
+        // row/col/value never change between iterations, so the row-block
+        // grouping is computed once here instead of once per iteration.
+        let blocks = group_entries_by_block(
+            self.sparse_matrix_persistor,
+            res.num_blocks(),
+            self.block_size,
+        );
+
+        let mut new_res = res;
+        for i in 0..max_iter {
+            let next = self.next_power(i, new_res, &blocks);
+            new_res = self.normalize(next);
+            fs::remove_file(format!("{}_matrix_{}", self.sparse_matrix_id, i)).unwrap();
+            info!(
+                "Done iter: {}. Dims: {}, entities: {}, num data points: {}.",
+                i,
+                self.dimension,
+                entities_count,
+                self.sparse_matrix_persistor.get_amount_of_data()
+            );
         }
+        info!("Done propagating.");
+        new_res
+    }
 
-        let row_sum = Arc::new(row_sum);
-        res.par_chunks_mut(entities_count * 4)
-            .enumerate()
-            .for_each(|(_i, chunk)| {
-                // i - number of dimension
-                // chunk - column/vector of bytes
-                for j in 0..entities_count {
-                    let sum = *row_sum.get(j).unwrap();
-
-                    let start_idx = j * 4;
-                    let end_idx = start_idx + 4;
-                    let pointer: *mut u8 = (&mut chunk[start_idx..end_idx]).as_mut_ptr();
-                    unsafe {
-                        let value = pointer as *mut f32;
-                        *value /= sum.sqrt();
-                    };
-                }
-            });
+    fn next_power(
+        &self,
+        iteration: u8,
+        res: BlockedMmapStorage,
+        blocks: &[Vec<Entry>],
+    ) -> BlockedMmapStorage {
+        let entities_count = self.sparse_matrix_persistor.get_entity_counter() as usize;
+
+        let file_name = format!("{}_matrix_{}", self.sparse_matrix_id, iteration + 1);
+        let output = BlockedMmapStorage::create(
+            file_name,
+            entities_count,
+            self.dimension as usize,
+            self.block_size,
+        );
+
+        let mut output = next_power_blocked(blocks, &res, output);
+        output.flush();
+        output
+    }
 
+    fn normalize(&self, res: BlockedMmapStorage) -> BlockedMmapStorage {
+        let mut res = normalize_storage(res);
         res.flush();
         res
     }
 
     fn persist<T1, T2>(
         &self,
-        res: MmapMut,
+        res: BlockedMmapStorage,
         entity_mapping_persistor: Arc<T1>,
         embedding_persistor: &mut T2,
     ) where
         T1: EntityMappingPersistor,
         T2: EmbeddingPersistor,
     {
-        info!("Start saving embeddings.");
+        persist_storage(
+            self.sparse_matrix_persistor,
+            self.dimension,
+            &res,
+            entity_mapping_persistor,
+            embedding_persistor,
+        );
+    }
+}
 
-        let entities_count = self.sparse_matrix_persistor.get_entity_counter();
-        embedding_persistor.put_metadata(entities_count, self.dimension);
-
-        for i in 0..entities_count {
-            let hash = self.sparse_matrix_persistor.get_hash(i);
-            let entity_name_opt = entity_mapping_persistor.get_entity(hash as u64);
-            if let Some(entity_name) = entity_name_opt {
-                let hash_occur = self
-                    .sparse_matrix_persistor
-                    .get_hash_occurrence(hash as u64);
-                let mut embedding: Vec<f32> = Vec::with_capacity(self.dimension as usize);
-                for j in 0..(self.dimension as usize) {
-                    let start_idx = ((j * entities_count as usize) + i as usize) * 4;
-                    let end_idx = start_idx + 4;
-                    let pointer: *const u8 = (&res[start_idx..end_idx]).as_ptr();
-                    let value = unsafe {
-                        let value = pointer as *const f32;
-                        *value
-                    };
-
-                    embedding.insert(j, value);
-                }
-                embedding_persistor.put_data(entity_name, hash_occur, embedding);
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixturePersistor {
+        entries: Vec<Entry>,
+    }
+
+    impl SparseMatrixPersistor for FixturePersistor {
+        fn get_entity_counter(&self) -> u32 {
+            4
+        }
+
+        fn get_hash(&self, i: u32) -> i64 {
+            i as i64
         }
 
-        embedding_persistor.finish();
+        fn get_hash_occurrence(&self, _hash: u64) -> u32 {
+            1
+        }
+
+        fn get_amount_of_data(&self) -> usize {
+            self.entries.len()
+        }
+
+        fn get_entry(&self, idx: usize) -> Entry {
+            self.entries[idx].clone()
+        }
+    }
+
+    fn fixture() -> FixturePersistor {
+        FixturePersistor {
+            entries: vec![
+                Entry {
+                    row: 0,
+                    col: 1,
+                    value: 0.5,
+                },
+                Entry {
+                    row: 1,
+                    col: 0,
+                    value: 0.5,
+                },
+                Entry {
+                    row: 2,
+                    col: 3,
+                    value: 1.0,
+                },
+                Entry {
+                    row: 3,
+                    col: 2,
+                    value: 1.0,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn blocked_propagation_matches_unblocked() {
+        let persistor = fixture();
+        let entities = persistor.get_entity_counter() as usize;
+        let dimension = 2;
+
+        let mut input = VecStorage::zeroed(entities, dimension);
+        for e in 0..entities {
+            for d in 0..dimension {
+                input.set(d, e, (e * dimension + d) as f32 + 1.0);
+            }
+        }
+
+        let unblocked = next_power_storage(&persistor, &input, VecStorage::zeroed(entities, dimension));
+
+        let block_size = 2;
+        let num_blocks = entities.div_ceil(block_size);
+        let blocks = group_entries_by_block(&persistor, num_blocks, block_size);
+
+        let prefix = std::env::temp_dir().join(format!(
+            "cleora_embedding_test_{}_{}",
+            std::process::id(),
+            entities
+        ));
+        let input_file = format!("{}_input", prefix.display());
+        let output_file = format!("{}_output", prefix.display());
+
+        let mut blocked_input =
+            BlockedMmapStorage::create(input_file.clone(), entities, dimension, block_size);
+        for e in 0..entities {
+            for d in 0..dimension {
+                blocked_input.set(d, e, input.get(d, e));
+            }
+        }
+
+        let blocked_output =
+            BlockedMmapStorage::create(output_file.clone(), entities, dimension, block_size);
+        let blocked = next_power_blocked(&blocks, &blocked_input, blocked_output);
+
+        for e in 0..entities {
+            for d in 0..dimension {
+                assert_eq!(unblocked.get(d, e), blocked.get(d, e));
+            }
+        }
 
-        info!("Done saving embeddings.");
+        std::fs::remove_file(&input_file).unwrap();
+        std::fs::remove_file(&output_file).unwrap();
     }
 }
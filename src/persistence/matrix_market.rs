@@ -0,0 +1,191 @@
+use crate::persistence::embedding::EmbeddingPersistor;
+use crate::persistence::sparse_matrix::Entry;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+const COORDINATE_BANNER: &str = "%%MatrixMarket matrix coordinate real general";
+const ARRAY_BANNER: &str = "%%MatrixMarket matrix array real general";
+
+/// Reads a Matrix Market coordinate file (`%%MatrixMarket matrix coordinate
+/// real general`) into `Entry` rows, so a precomputed adjacency /
+/// co-occurrence matrix can be loaded straight from disk instead of only
+/// being built from raw relation columns.
+///
+/// `%`-prefixed comment lines are skipped. Indices in the file are 1-based;
+/// they are decremented to the 0-based indices `SparseMatrixPersistor`
+/// expects.
+pub fn read_matrix_market_entries(reader: impl io::Read) -> io::Result<Vec<Entry>> {
+    let mut lines = BufReader::new(reader).lines();
+
+    let banner = lines
+        .next()
+        .ok_or_else(|| invalid_data("empty Matrix Market file"))??;
+    if banner.trim() != COORDINATE_BANNER {
+        return Err(invalid_data(&format!(
+            "unsupported Matrix Market banner: {}",
+            banner
+        )));
+    }
+
+    let dims_line = loop {
+        let line = lines
+            .next()
+            .ok_or_else(|| invalid_data("missing Matrix Market dimension line"))??;
+        if !line.trim_start().starts_with('%') {
+            break line;
+        }
+    };
+
+    let mut dims = dims_line.split_whitespace();
+    let rows: u32 = parse_field(dims.next())?;
+    let cols: u32 = parse_field(dims.next())?;
+    let nnz: usize = parse_field(dims.next())?;
+
+    let mut entries = Vec::with_capacity(nnz);
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() || line.trim_start().starts_with('%') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let row: u32 = parse_field(fields.next())?;
+        let col: u32 = parse_field(fields.next())?;
+        let value: f32 = parse_field(fields.next())?;
+        if row < 1 || row > rows || col < 1 || col > cols {
+            return Err(invalid_data(&format!(
+                "Matrix Market row/col out of range: {} {}",
+                row, col
+            )));
+        }
+        entries.push(Entry {
+            row: row - 1,
+            col: col - 1,
+            value,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&str>) -> io::Result<T> {
+    field
+        .ok_or_else(|| invalid_data("missing Matrix Market field"))?
+        .parse()
+        .map_err(|_| invalid_data("malformed Matrix Market field"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes the final entity-by-dimension embedding matrix out in Matrix
+/// Market dense array format, so it can be consumed by the wider
+/// scientific-computing ecosystem (SciPy, nalgebra, MATLAB).
+pub struct MatrixMarketEmbeddingPersistor {
+    file_name: String,
+    dimension: u16,
+    rows: Vec<Vec<f32>>,
+}
+
+impl MatrixMarketEmbeddingPersistor {
+    pub fn new(file_name: String) -> Self {
+        MatrixMarketEmbeddingPersistor {
+            file_name,
+            dimension: 0,
+            rows: Vec::new(),
+        }
+    }
+}
+
+impl EmbeddingPersistor for MatrixMarketEmbeddingPersistor {
+    fn put_metadata(&mut self, entities_count: u32, dimension: u16) {
+        self.dimension = dimension;
+        self.rows = Vec::with_capacity(entities_count as usize);
+    }
+
+    fn put_data(&mut self, _entity: String, _occur_count: u32, embedding: Vec<f32>) {
+        self.rows.push(embedding);
+    }
+
+    fn finish(&mut self) {
+        let file = File::create(&self.file_name).unwrap();
+        let mut writer = BufWriter::new(file);
+
+        writeln!(writer, "{}", ARRAY_BANNER).unwrap();
+        writeln!(writer, "{} {}", self.rows.len(), self.dimension).unwrap();
+
+        // Matrix Market array format is column-major.
+        for dim in 0..self.dimension as usize {
+            for row in &self.rows {
+                writeln!(writer, "{}", row[dim]).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_entries_with_comments_and_converts_to_0_based() {
+        let input = "%%MatrixMarket matrix coordinate real general\n\
+                      % a comment\n\
+                      2 3 2\n\
+                      1 1 0.5\n\
+                      2 3 1.5\n";
+
+        let entries = read_matrix_market_entries(input.as_bytes()).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].row, entries[0].col, entries[0].value), (0, 0, 0.5));
+        assert_eq!((entries[1].row, entries[1].col, entries[1].value), (1, 2, 1.5));
+    }
+
+    #[test]
+    fn rejects_wrong_banner() {
+        let input = "%%MatrixMarket matrix array real general\n1 1 1\n1 1 1.0\n";
+        let err = read_matrix_market_entries(input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_field() {
+        let input = "%%MatrixMarket matrix coordinate real general\n1 1 1\nnot_a_row 1 1.0\n";
+        let err = read_matrix_market_entries(input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_out_of_range_index() {
+        let input = "%%MatrixMarket matrix coordinate real general\n1 1 1\n0 1 1.0\n";
+        let err = read_matrix_market_entries(input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_embeddings_in_column_major_order() {
+        let file_name = format!(
+            "{}/cleora_matrix_market_test_{}",
+            std::env::temp_dir().display(),
+            std::process::id()
+        );
+
+        let mut persistor = MatrixMarketEmbeddingPersistor::new(file_name.clone());
+        persistor.put_metadata(2, 3);
+        persistor.put_data("a".to_string(), 1, vec![1.0, 2.0, 3.0]);
+        persistor.put_data("b".to_string(), 1, vec![4.0, 5.0, 6.0]);
+        persistor.finish();
+
+        let contents = std::fs::read_to_string(&file_name).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+
+        let mut lines = contents.lines();
+        assert_eq!(lines.next().unwrap(), ARRAY_BANNER);
+        assert_eq!(lines.next().unwrap(), "2 3");
+
+        let values: Vec<&str> = lines.collect();
+        assert_eq!(values, vec!["1", "4", "2", "5", "3", "6"]);
+    }
+}